@@ -4,6 +4,7 @@ use std::io::BufReader;
 use std::sync::Arc;
 use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{timeout, Duration};
 use tokio_rustls::rustls;
 use tokio_rustls::server::TlsStream;
 use tokio_rustls::TlsAcceptor;
@@ -18,7 +19,8 @@ async fn main() -> io::Result<()> {
     let tls_acceptor = TlsAcceptor::from(tls_config.await);
     let listening_addr = format!("{}:{}", proxy_options.bind_address, proxy_options.bind_port);
     let listener = TcpListener::bind(listening_addr).await?;
-    run_server(listener, tls_acceptor).await;
+    let allowed_destinations = Arc::new(proxy_options.allowed_destinations);
+    run_server(listener, tls_acceptor, allowed_destinations).await;
     Ok(())
 }
 
@@ -30,22 +32,79 @@ fn parse_cli() -> ProxyOptions {
     let bind_port = matches.value_of("bind-port").unwrap_or("8080");
     let cert_path = matches.value_of("cert-path").unwrap_or("MyCertificate.crt");
     let key_path = matches.value_of("key-path").unwrap_or("MyKey.key");
+    let client_ca_path = matches.value_of("client-ca-path");
+    let allowlist_path = matches.value_of("allowlist");
+    let sni_config_path = matches.value_of("sni-config");
+
+    let sni_identities = sni_config_path.map_or_else(Vec::new, load_sni_identities);
+
+    let (certs, private_key) = if sni_identities.is_empty() {
+        (Some(load_certs(cert_path)), Some(load_private_key(key_path)))
+    } else {
+        (None, None)
+    };
 
     ProxyOptions {
         bind_address: bind_address.to_string(),
         bind_port: bind_port.to_string(),
-        certs: load_certs(cert_path),
-        private_key: load_private_key(key_path),
+        certs,
+        private_key,
+        client_ca_roots: client_ca_path.map(load_root_cert_store),
+        allowed_destinations: allowlist_path.map_or_else(
+            || PERMITTED_DESTINATIONS.iter().map(|s| s.to_string()).collect(),
+            load_allowlist,
+        ),
+        sni_identities,
     }
 }
 
 struct ProxyOptions {
     bind_address: String,
     bind_port: String,
+    certs: Option<Vec<rustls::Certificate>>,
+    private_key: Option<rustls::PrivateKey>,
+    client_ca_roots: Option<rustls::RootCertStore>,
+    allowed_destinations: Vec<String>,
+    sni_identities: Vec<SniIdentity>,
+}
+
+struct SniIdentity {
+    hostname: String,
     certs: Vec<rustls::Certificate>,
     private_key: rustls::PrivateKey,
 }
 
+fn load_sni_identities(filepath: &str) -> Vec<SniIdentity> {
+    let contents = fs::read_to_string(filepath).expect("cannot open SNI config file");
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let (hostname, cert_path, key_path) = match fields.as_slice() {
+                [hostname, cert_path, key_path] => (hostname, cert_path, key_path),
+                _ => panic!("invalid SNI config line, expected \"hostname cert_path key_path\": {:?}", line),
+            };
+            SniIdentity {
+                hostname: hostname.to_string(),
+                certs: load_certs(cert_path),
+                private_key: load_private_key(key_path),
+            }
+        })
+        .collect()
+}
+
+fn load_allowlist(filepath: &str) -> Vec<String> {
+    let contents = fs::read_to_string(filepath).expect("cannot open allowlist file");
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| line.to_string())
+        .collect()
+}
+
 fn load_certs(filepath: &str) -> Vec<rustls::Certificate> {
     let certfile = fs::File::open(filepath).expect("cannot open certificate file");
     let mut reader = BufReader::new(certfile);
@@ -72,48 +131,150 @@ fn load_private_key(filepath: &str) -> rustls::PrivateKey {
     panic!("no key found in {:?}", filepath);
 }
 
+fn load_root_cert_store(filepath: &str) -> rustls::RootCertStore {
+    let cafile = fs::File::open(filepath).expect("cannot open CA bundle file");
+    let mut reader = BufReader::new(cafile);
+    let ca_certs = rustls_pemfile::certs(&mut reader).expect("cannot parse CA bundle .pem file");
+
+    let mut roots = rustls::RootCertStore::empty();
+    for cert in ca_certs {
+        roots
+            .add(&rustls::Certificate(cert))
+            .expect("invalid CA certificate");
+    }
+    roots
+}
+
 async fn build_tls_config(proxy_options: &ProxyOptions) -> Arc<rustls::ServerConfig> {
-    let config = rustls::ServerConfig::builder()
-        .with_safe_defaults()
-        .with_no_client_auth()
-        .with_single_cert(
-            proxy_options.certs.clone(),
-            proxy_options.private_key.clone(),
-        )
-        .expect("Unable to create TLS config");
+    let config_builder = rustls::ServerConfig::builder().with_safe_defaults();
+
+    let config = match &proxy_options.client_ca_roots {
+        Some(roots) => {
+            let config_builder = config_builder.with_client_cert_verifier(
+                rustls::server::AllowAnyAuthenticatedClient::new(roots.clone()),
+            );
+            if proxy_options.sni_identities.is_empty() {
+                config_builder
+                    .with_single_cert(default_certs(proxy_options), default_private_key(proxy_options))
+                    .expect("Unable to create TLS config")
+            } else {
+                config_builder.with_cert_resolver(build_sni_cert_resolver(proxy_options))
+            }
+        }
+        None => {
+            let config_builder = config_builder.with_no_client_auth();
+            if proxy_options.sni_identities.is_empty() {
+                config_builder
+                    .with_single_cert(default_certs(proxy_options), default_private_key(proxy_options))
+                    .expect("Unable to create TLS config")
+            } else {
+                config_builder.with_cert_resolver(build_sni_cert_resolver(proxy_options))
+            }
+        }
+    };
+
     Arc::new(config)
 }
 
-async fn run_server(listener: TcpListener, tls_acceptor: TlsAcceptor) {
+fn default_certs(proxy_options: &ProxyOptions) -> Vec<rustls::Certificate> {
+    proxy_options
+        .certs
+        .clone()
+        .expect("no certificate configured: pass --cert-path or --sni-config")
+}
+
+fn default_private_key(proxy_options: &ProxyOptions) -> rustls::PrivateKey {
+    proxy_options
+        .private_key
+        .clone()
+        .expect("no private key configured: pass --key-path or --sni-config")
+}
+
+fn build_sni_cert_resolver(
+    proxy_options: &ProxyOptions,
+) -> Arc<rustls::server::ResolvesServerCertUsingSni> {
+    let mut resolver = rustls::server::ResolvesServerCertUsingSni::new();
+    for identity in &proxy_options.sni_identities {
+        let signing_key = rustls::sign::any_supported_type(&identity.private_key)
+            .expect("unsupported private key type");
+        let certified_key = rustls::sign::CertifiedKey::new(identity.certs.clone(), signing_key);
+        resolver
+            .add(&identity.hostname, certified_key)
+            .expect("invalid SNI hostname/certificate pair");
+    }
+    Arc::new(resolver)
+}
+
+async fn run_server(
+    listener: TcpListener,
+    tls_acceptor: TlsAcceptor,
+    allowed_destinations: Arc<Vec<String>>,
+) {
     loop {
         let (client_stream, _) = unwrap_or_continue!(listener.accept().await);
+        let tls_acceptor = tls_acceptor.clone();
+        let allowed_destinations = allowed_destinations.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = handle_client(client_stream, tls_acceptor, allowed_destinations).await
+            {
+                eprintln!("error handling client: {}", e);
+            }
+        });
+    }
+}
 
-        let mut client_stream_tls =
-            unwrap_or_continue!(establish_tls(client_stream, &tls_acceptor).await);
+async fn handle_client(
+    client_stream: TcpStream,
+    tls_acceptor: TlsAcceptor,
+    allowed_destinations: Arc<Vec<String>>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut client_stream_tls = establish_tls(client_stream, &tls_acceptor).await?;
+
+    let http_request_line = match read_http_request(&mut client_stream_tls).await {
+        Ok(line) => line,
+        Err(e) => {
+            if e.is::<RequestTooLargeError>() {
+                drop(e);
+                send_request_too_large_status(&mut client_stream_tls).await?;
+                return Ok(());
+            }
+            if e.is::<RequestTimeoutError>() {
+                drop(e);
+                send_request_timeout_status(&mut client_stream_tls).await?;
+                return Ok(());
+            }
+            return Err(e);
+        }
+    };
 
-        let http_request_line =
-            unwrap_or_continue!(read_http_request(&mut client_stream_tls).await);
+    let rcvd_http_request = parse_http_request(&http_request_line).await?;
 
-        let rcvd_http_request = unwrap_or_continue!(parse_http_request(&http_request_line).await);
+    if !is_http_connect(&rcvd_http_request).await {
+        send_unsupported_method_status(&mut client_stream_tls).await?;
+        return Ok(());
+    }
 
-        if !is_http_connect(&rcvd_http_request).await {
-            unwrap_or_continue!(send_unsupported_method_status(&mut client_stream_tls).await);
-            continue;
-        }
+    if !is_permitted_destination(&rcvd_http_request.uri, &allowed_destinations).await {
+        send_forbidden_status(&mut client_stream_tls).await?;
+        return Ok(());
+    }
 
-        if !is_permitted_destination(&rcvd_http_request.uri).await {
-            unwrap_or_continue!(send_forbidden_status(&mut client_stream_tls).await);
-            continue;
+    let mut dst_stream = match TcpStream::connect(rcvd_http_request.uri).await {
+        Ok(stream) => stream,
+        Err(_) => {
+            send_bad_gateway_status(&mut client_stream_tls).await?;
+            return Ok(());
         }
+    };
 
-        let mut dst_stream = unwrap_or_continue!(TcpStream::connect(rcvd_http_request.uri).await);
+    send_ok_status(&mut client_stream_tls).await?;
 
-        unwrap_or_continue!(send_ok_status(&mut client_stream_tls).await);
+    client_stream_tls.flush().await?;
 
-        unwrap_or_continue!(client_stream_tls.flush().await);
+    io::copy_bidirectional(&mut client_stream_tls, &mut dst_stream).await?;
 
-        unwrap_or_continue!(io::copy_bidirectional(&mut client_stream_tls, &mut dst_stream).await);
-    }
+    Ok(())
 }
 
 #[macro_export]
@@ -134,34 +295,113 @@ async fn establish_tls(
     acceptor.accept(stream).await
 }
 
+const MAX_HEADER_SIZE: usize = 8192;
+const REQUEST_TERMINATOR: &str = "\r\n\r\n";
+const REQUEST_READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+struct RequestTooLargeError;
+
+impl std::fmt::Display for RequestTooLargeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "request exceeded max header size of {} bytes", MAX_HEADER_SIZE)
+    }
+}
+
+impl std::error::Error for RequestTooLargeError {}
+
+#[derive(Debug)]
+struct RequestTimeoutError;
+
+impl std::fmt::Display for RequestTimeoutError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "no request terminator received within {:?}", REQUEST_READ_TIMEOUT)
+    }
+}
+
+impl std::error::Error for RequestTimeoutError {}
+
 async fn read_http_request(
     client_stream: &mut TlsStream<TcpStream>,
-) -> Result<String, Box<dyn std::error::Error>> {
-    let mut buffer: [u8; 512] = [0; 512];
-    client_stream.read(&mut buffer).await?;
-    match std::str::from_utf8(&buffer) {
-        Ok(s) => Ok(s.to_string()),
-        Err(e) => Err(Box::new(e)),
+) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    match timeout(REQUEST_READ_TIMEOUT, read_until_terminator(client_stream)).await {
+        Ok(result) => result,
+        Err(_) => Err(Box::new(RequestTimeoutError)),
     }
 }
 
-async fn parse_http_request(req: &str) -> Result<HttpRequest, Box<dyn std::error::Error>> {
-    let req = req.split(' ').collect::<Vec<&str>>();
-    let invalid_http_req_err = Box::new(std::io::Error::new(
-        std::io::ErrorKind::InvalidData,
-        "Invalid HTTP request",
-    ));
-    let method = match req.get(0) {
-        Some(m) => m.to_string(),
-        None => return Err(invalid_http_req_err),
+async fn read_until_terminator(
+    client_stream: &mut TlsStream<TcpStream>,
+) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    let mut buffer: Vec<u8> = Vec::new();
+    let mut chunk: [u8; 512] = [0; 512];
+
+    loop {
+        let n = client_stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "connection closed before request terminator",
+            )));
+        }
+        buffer.extend_from_slice(&chunk[..n]);
+
+        if buffer.len() > MAX_HEADER_SIZE {
+            return Err(Box::new(RequestTooLargeError));
+        }
+
+        match std::str::from_utf8(&buffer) {
+            Ok(s) if s.contains(REQUEST_TERMINATOR) => return Ok(s.to_string()),
+            _ => {}
+        }
+    }
+}
+
+async fn parse_http_request(req: &str) -> Result<HttpRequest, Box<dyn std::error::Error + Send + Sync>> {
+    let invalid_http_req_err = || {
+        Box::new(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "Invalid HTTP request",
+        )) as Box<dyn std::error::Error + Send + Sync>
     };
-    let uri = match req.get(1) {
-        Some(u) => u.to_string(),
-        None => return Err(invalid_http_req_err),
+
+    let request_line = req.lines().next().ok_or_else(invalid_http_req_err)?;
+    let tokens = request_line.split(' ').collect::<Vec<&str>>();
+
+    let (method, uri, version) = match tokens.as_slice() {
+        [method, uri, version] => (method.to_string(), uri.to_string(), *version),
+        _ => return Err(invalid_http_req_err()),
     };
+
+    if !is_valid_method(&method) || !is_valid_authority(&uri) || !is_valid_http_version(version) {
+        return Err(invalid_http_req_err());
+    }
+
     Ok(HttpRequest { method, uri })
 }
 
+const VALID_HTTP_METHODS: &[&str] = &[
+    "GET", "HEAD", "POST", "PUT", "DELETE", "CONNECT", "OPTIONS", "TRACE", "PATCH",
+];
+
+fn is_valid_method(method: &str) -> bool {
+    VALID_HTTP_METHODS.contains(&method)
+}
+
+fn is_valid_authority(authority: &str) -> bool {
+    match authority.rsplit_once(':') {
+        Some((host, port)) => !host.is_empty() && port.parse::<u16>().is_ok(),
+        None => false,
+    }
+}
+
+fn is_valid_http_version(version: &str) -> bool {
+    match version.strip_prefix("HTTP/1.") {
+        Some(minor) => minor.len() == 1 && minor.chars().all(|c| c.is_ascii_digit()),
+        None => false,
+    }
+}
+
 #[derive(Debug, PartialEq)]
 struct HttpRequest {
     method: String,
@@ -175,8 +415,32 @@ async fn is_http_connect(req: &HttpRequest) -> bool {
     false
 }
 
-async fn is_permitted_destination(url: &str) -> bool {
-    PERMITTED_DESTINATIONS.contains(&url)
+async fn is_permitted_destination(url: &str, allowed_destinations: &[String]) -> bool {
+    allowed_destinations
+        .iter()
+        .any(|pattern| destination_matches(pattern, url))
+}
+
+fn destination_matches(pattern: &str, url: &str) -> bool {
+    let (pattern_host, pattern_port) = match pattern.rsplit_once(':') {
+        Some((host, port)) => (host, port),
+        None => return false,
+    };
+    let (url_host, url_port) = match url.rsplit_once(':') {
+        Some((host, port)) => (host, port),
+        None => return false,
+    };
+
+    if pattern_port != "*" && pattern_port != url_port {
+        return false;
+    }
+
+    match pattern_host.strip_prefix("*.") {
+        Some(suffix) => url_host
+            .strip_suffix(suffix)
+            .is_some_and(|prefix| prefix.ends_with('.')),
+        None => pattern_host == url_host,
+    }
 }
 
 async fn send_unsupported_method_status(stream: &mut TlsStream<TcpStream>) -> io::Result<()> {
@@ -191,6 +455,24 @@ async fn send_forbidden_status(stream: &mut TlsStream<TcpStream>) -> io::Result<
     Ok(())
 }
 
+async fn send_request_too_large_status(stream: &mut TlsStream<TcpStream>) -> io::Result<()> {
+    let status_msg = create_http_status(413, "Request Entity Too Large").await;
+    send_http_status(stream, &status_msg).await?;
+    Ok(())
+}
+
+async fn send_request_timeout_status(stream: &mut TlsStream<TcpStream>) -> io::Result<()> {
+    let status_msg = create_http_status(408, "Request Timeout").await;
+    send_http_status(stream, &status_msg).await?;
+    Ok(())
+}
+
+async fn send_bad_gateway_status(stream: &mut TlsStream<TcpStream>) -> io::Result<()> {
+    let status_msg = create_http_status(502, "Bad Gateway").await;
+    send_http_status(stream, &status_msg).await?;
+    Ok(())
+}
+
 async fn send_ok_status(stream: &mut TlsStream<TcpStream>) -> io::Result<()> {
     let status_msg = create_http_status(200, "OK").await;
     send_http_status(stream, &status_msg).await?;
@@ -218,15 +500,82 @@ mod tests {
 
     #[tokio::test]
     async fn test_is_permitted_destination() {
+        let allowed_destinations: Vec<String> = PERMITTED_DESTINATIONS
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+
+        let cases = vec![
+            TestCase {
+                input: "api.giphy.com:443",
+                expected: true,
+            },
+            TestCase {
+                input: "api.giphy.com:80",
+                expected: false,
+            },
+            TestCase {
+                input: "different.url.com:443",
+                expected: false,
+            },
+        ];
+
+        for c in cases {
+            assert_eq!(
+                is_permitted_destination(c.input, &allowed_destinations).await,
+                c.expected
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_is_permitted_destination_wildcard_subdomain() {
+        let allowed_destinations = vec!["*.giphy.com:443".to_string()];
+
         let cases = vec![
             TestCase {
                 input: "api.giphy.com:443",
                 expected: true,
             },
+            TestCase {
+                input: "media.giphy.com:443",
+                expected: true,
+            },
+            TestCase {
+                input: "giphy.com:443",
+                expected: false,
+            },
             TestCase {
                 input: "api.giphy.com:80",
                 expected: false,
             },
+            TestCase {
+                input: "evilgiphy.com:443",
+                expected: false,
+            },
+        ];
+
+        for c in cases {
+            assert_eq!(
+                is_permitted_destination(c.input, &allowed_destinations).await,
+                c.expected
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_is_permitted_destination_any_port() {
+        let allowed_destinations = vec!["api.giphy.com:*".to_string()];
+
+        let cases = vec![
+            TestCase {
+                input: "api.giphy.com:443",
+                expected: true,
+            },
+            TestCase {
+                input: "api.giphy.com:8080",
+                expected: true,
+            },
             TestCase {
                 input: "different.url.com:443",
                 expected: false,
@@ -234,7 +583,10 @@ mod tests {
         ];
 
         for c in cases {
-            assert_eq!(is_permitted_destination(&c.input).await, c.expected);
+            assert_eq!(
+                is_permitted_destination(c.input, &allowed_destinations).await,
+                c.expected
+            );
         }
     }
 
@@ -274,27 +626,27 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn test_parse_http_request_get_should_pass() {
+    async fn test_parse_http_request_get_with_relative_path_should_fail() {
         let line = "GET / HTTP/1.1\r\n\r\n";
-        assert_eq!(
-            parse_http_request(line).await.unwrap(),
-            HttpRequest {
-                method: "GET".to_string(),
-                uri: "/".to_string(),
-            }
-        );
+        assert!(parse_http_request(line).await.is_err());
     }
 
     #[tokio::test]
-    async fn test_parse_http_request_invalid_input_should_pass() {
-        let line = "This is some test text";
-        assert_eq!(
-            parse_http_request(line).await.unwrap(),
-            HttpRequest {
-                method: "This".to_string(),
-                uri: "is".to_string(),
-            }
-        );
+    async fn test_parse_http_request_unknown_method_should_fail() {
+        let line = "This example.com:443 HTTP/1.1\r\n\r\n";
+        assert!(parse_http_request(line).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_parse_http_request_bad_authority_should_fail() {
+        let line = "CONNECT example.com HTTP/1.1\r\n\r\n";
+        assert!(parse_http_request(line).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_parse_http_request_bad_version_should_fail() {
+        let line = "CONNECT example.com:443 HTTP/2.0\r\n\r\n";
+        assert!(parse_http_request(line).await.is_err());
     }
 
     #[tokio::test]